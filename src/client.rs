@@ -1,97 +1,685 @@
 //! The `Client` allows users of the `raft` library to connect to remote `Server` instances and
 //! issue commands to be applied to the `StateMachine`.
 
-use std::collections::HashSet;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::mem;
 use std::net::SocketAddr;
 use std::net::TcpStream;
 use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use bufstream::BufStream;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use capnp::{serialize, MessageReader, ReaderOptions};
+use snow::Builder as NoiseBuilder;
+use snow::Session as NoiseSession;
 
-use messages_capnp::{client_response, proposal_response};
+use messages_capnp::{client_response, proposal_response, query_response};
 use messages;
 use ClientId;
 use Result;
 use RaftError;
 
+/// Noise protocol parameters for the client<->server transport: the client (initiator) knows
+/// the server's static public key ahead of time, and presents its own static key during the
+/// handshake rather than it being known in advance.
+const NOISE_PARAMS: &'static str = "Noise_XK_25519_ChaChaPoly_BLAKE2b";
+
+/// The largest length-prefixed Noise frame this client will allocate a buffer for, on both the
+/// handshake and transport-mode framing paths. Bounds the damage an unauthenticated peer can do
+/// by sending a bogus length prefix before (or instead of) a real handshake/message.
+const MAX_NOISE_FRAME_LEN: u32 = 1 << 20;
+
+/// Wire protocol versions this client is able to speak, advertised in every
+/// `client_connection_preamble` so the server can pick the newest one both sides understand.
+/// Adding a new version here (e.g. to support a new field like the proposal request id) is how
+/// the wire format evolves without breaking interoperability with older servers.
+const SUPPORTED_PROTOCOL_VERSIONS: &'static [u32] = &[1];
+
+/// A cluster member the client may connect to.
+///
+/// If `noise_key` is set, the connection to this member is transport-encrypted with Noise
+/// before the `client_connection_preamble` is sent, and the handshake verifies that the
+/// member presents this static public key. If it is `None`, the connection is left
+/// plaintext, e.g. while a cluster is being migrated onto encrypted transport.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClusterMember {
+    /// The address to connect to.
+    pub addr: SocketAddr,
+    /// The member's expected Noise static public key, 32 bytes for `Noise_XK_25519_ChaChaPoly_BLAKE2b`.
+    pub noise_key: Option<[u8; 32]>,
+}
+
+impl ClusterMember {
+    /// A cluster member reachable only over plaintext transport.
+    pub fn plaintext(addr: SocketAddr) -> ClusterMember {
+        ClusterMember { addr: addr, noise_key: None }
+    }
+
+    /// A cluster member reachable over a Noise-encrypted transport, expected to present
+    /// `noise_key` as its static public key during the handshake.
+    pub fn encrypted(addr: SocketAddr, noise_key: [u8; 32]) -> ClusterMember {
+        ClusterMember { addr: addr, noise_key: Some(noise_key) }
+    }
+}
+
 /// The representation of a Client connection to the cluster.
+///
+/// `Client` is safe to share between threads: proposals are tagged with a monotonically
+/// increasing request id so that many of them can be pipelined in flight on a single
+/// leader connection at once, with responses matched back up to their waiters as they
+/// arrive out of order.
 pub struct Client {
     /// The `Uuid` of the client, should be unique in the cluster.
     pub id: ClientId,
     /// The current connection to the current leader.
     /// If it is none it may mean that there is no estabished leader or that there has been
     /// a disconnection.
-    leader_connection: Option<BufStream<TcpStream>>,
+    leader_connection: Mutex<Option<Arc<LeaderConnection>>>,
     /// A lookup for the cluster's nodes.
-    cluster: HashSet<SocketAddr>,
+    cluster: HashSet<ClusterMember>,
+    /// Source of ids for in-flight proposals, so responses read by the background reader
+    /// can be correlated back to the `propose` call that is waiting on them.
+    next_request_id: AtomicU64,
+    /// This client's own Noise static keypair, generated fresh at construction and presented
+    /// to any member whose `noise_key` requires an encrypted handshake.
+    noise_keypair: snow::Keypair,
+    /// Timeout and retry behavior for `propose`.
+    config: ClientConfig,
+}
+
+/// Controls how long `Client::propose` is willing to wait for a proposal to commit, and how
+/// it paces retries across the cluster while waiting.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The maximum amount of time a single call to `propose` may take before it gives up and
+    /// returns `RaftError::Timeout`, regardless of how many leaders it has tried.
+    pub propose_timeout: Duration,
+    /// The number of further full passes over the cluster allowed after the first exhausted
+    /// one (each pass yielding only `UnknownLeader` responses or timeouts) before `propose`
+    /// gives up early with `RaftError::LeaderSearchExhausted`; `0` gives up after one pass.
+    pub max_retries: usize,
+    /// The delay before retrying after an exhausted pass over the cluster. Doubles after each
+    /// exhausted pass, capped at `propose_timeout`.
+    pub backoff: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            propose_timeout: Duration::from_secs(10),
+            max_retries: 5,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+/// A single outstanding connection to a (believed) leader.
+///
+/// The write half is guarded by a mutex so that concurrent `propose` calls don't
+/// interleave framed capnp messages on the wire. The read half is driven by a dedicated
+/// background thread that demultiplexes `client_response`s by request id.
+struct LeaderConnection {
+    addr: SocketAddr,
+    writer: Mutex<Transport>,
+    pending: Arc<Mutex<HashMap<u64, Waiter>>>,
+    /// The protocol version the server selected from `SUPPORTED_PROTOCOL_VERSIONS`, filled in
+    /// by the background reader once the first `client_response` on this connection arrives.
+    /// Exposed via `Client::negotiated_protocol_version`. Today this is used only to detect an
+    /// incompatible server (see `RaftError::VersionMismatch` in `spawn_reader`); there is only one
+    /// supported version so far, so there is no per-version encode/decode path to select yet.
+    negotiated_version: Arc<Mutex<Option<u32>>>,
+}
+
+/// The waiter registered for an in-flight tagged request, holding the sender half of the
+/// channel that `propose`/`query` is blocked on. Kept as an enum rather than two separate
+/// pending maps because proposals and queries share a single request id space per connection.
+enum Waiter {
+    Proposal(Sender<Result<()>>),
+    Query(Sender<Result<Vec<u8>>>),
+}
+
+impl Waiter {
+    fn complete_proposal(self, result: Result<()>) {
+        if let Waiter::Proposal(tx) = self {
+            let _ = tx.send(result);
+        }
+    }
+
+    fn complete_query(self, result: Result<Vec<u8>>) {
+        if let Waiter::Query(tx) = self {
+            let _ = tx.send(result);
+        }
+    }
+
+    fn fail(self, err: RaftError) {
+        match self {
+            Waiter::Proposal(tx) => { let _ = tx.send(Err(err)); },
+            Waiter::Query(tx) => { let _ = tx.send(Err(err)); },
+        }
+    }
+}
+
+/// Either a plaintext buffered TCP stream, or one wrapped in a Noise transport that
+/// transparently encrypts/decrypts framed messages as they're written and read. Capnp's
+/// `serialize::write_message`/`read_message` only need `Read`/`Write`, so everywhere else in
+/// this module can stay oblivious to which kind of connection it has.
+enum Transport {
+    Plain(BufStream<TcpStream>),
+    Encrypted(NoiseTransport),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut stream) => stream.read(buf),
+            Transport::Encrypted(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut stream) => stream.write(buf),
+            Transport::Encrypted(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Plain(ref mut stream) => stream.flush(),
+            Transport::Encrypted(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Transport {
+    fn try_clone(&self) -> io::Result<Transport> {
+        match *self {
+            Transport::Plain(ref stream) => Ok(Transport::Plain(BufStream::new(try!(stream.get_ref().try_clone())))),
+            Transport::Encrypted(ref stream) => Ok(Transport::Encrypted(try!(stream.try_clone()))),
+        }
+    }
+}
+
+/// A length-prefixed, Noise-encrypted framing layer over a `BufStream<TcpStream>`. Each
+/// `write`d capnp message is buffered in full and only actually encrypted and sent on
+/// `flush`, matching how `serialize::write_message` is always followed by a `flush` call in
+/// this module. Reads pull one length-prefixed ciphertext frame at a time and decrypt it
+/// before handing bytes back to the caller.
+struct NoiseTransport {
+    inner: BufStream<TcpStream>,
+    session: Arc<Mutex<NoiseSession>>,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+}
+
+impl NoiseTransport {
+    fn try_clone(&self) -> io::Result<NoiseTransport> {
+        Ok(NoiseTransport {
+            inner: BufStream::new(try!(self.inner.get_ref().try_clone())),
+            session: self.session.clone(),
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        })
+    }
+}
+
+impl Read for NoiseTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            let len = try!(self.inner.read_u32::<BigEndian>());
+            if len > MAX_NOISE_FRAME_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "noise frame exceeds maximum size"));
+            }
+            let mut ciphertext = vec![0u8; len as usize];
+            try!(self.inner.read_exact(&mut ciphertext));
+            let mut plaintext = vec![0u8; len as usize];
+            let n = try!(self.session.lock().unwrap().read_message(&ciphertext, &mut plaintext)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("noise decrypt failed: {:?}", e))));
+            plaintext.truncate(n);
+            self.incoming = plaintext;
+        }
+        let n = ::std::cmp::min(buf.len(), self.incoming.len());
+        buf[..n].copy_from_slice(&self.incoming[..n]);
+        self.incoming.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for NoiseTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.outgoing.is_empty() {
+            return self.inner.flush();
+        }
+        let plaintext = mem::replace(&mut self.outgoing, Vec::new());
+        // Noise messages may grow by up to 16 bytes of AEAD tag.
+        let mut ciphertext = vec![0u8; plaintext.len() + 16];
+        let len = try!(self.session.lock().unwrap().write_message(&plaintext, &mut ciphertext)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("noise encrypt failed: {:?}", e))));
+        try!(self.inner.write_u32::<BigEndian>(len as u32));
+        try!(self.inner.write_all(&ciphertext[..len]));
+        self.inner.flush()
+    }
 }
 
 impl Client {
 
-    /// Creates a new client.
-    pub fn new(cluster: HashSet<SocketAddr>) -> Client {
+    /// Creates a new client with the default `ClientConfig`.
+    pub fn new(cluster: HashSet<ClusterMember>) -> Client {
+        Client::with_config(cluster, ClientConfig::default())
+    }
+
+    /// Creates a new client with an explicit `ClientConfig`, controlling how long `propose`
+    /// is willing to wait and how aggressively it retries.
+    pub fn with_config(cluster: HashSet<ClusterMember>, config: ClientConfig) -> Client {
+        let noise_keypair = NoiseBuilder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .expect("failed to generate noise keypair");
         Client {
             id: ClientId::new(),
-            leader_connection: None,
+            leader_connection: Mutex::new(None),
             cluster: cluster,
+            next_request_id: AtomicU64::new(0),
+            noise_keypair: noise_keypair,
+            config: config,
         }
     }
 
     /// Proposes an entry to be appended to the replicated log. This will only
     /// return once the entry has been durably committed.
     /// Returns `Error` when the entire cluster has an unknown leader. Try proposing again later.
-    pub fn propose(&mut self, entry: &[u8]) -> Result<()> {
+    ///
+    /// Multiple proposals may be in flight concurrently, including from other threads sharing
+    /// this `Client`: each is tagged with its own request id and can be resolved independently
+    /// of the order in which the leader responds.
+    ///
+    /// Bounded by `self.config.propose_timeout`: once that much wall-clock time has elapsed,
+    /// `propose` returns `RaftError::Timeout` rather than retrying forever, giving callers a
+    /// predictable latency ceiling.
+    pub fn propose(&self, entry: &[u8]) -> Result<()> {
         scoped_trace!("{:?}: propose", self);
-        let mut message = messages::proposal_request(entry);
+        let deadline = Instant::now() + self.config.propose_timeout;
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let mut redirect = None;
+        let mut backoff = self.config.backoff;
+        let mut passes = 0;
+        let mut tried = HashSet::new();
+
+        loop {
+            let connection = try!(self.acquire_connection(&mut tried, &mut redirect,
+                                                           &mut backoff, &mut passes, deadline));
+
+            let (tx, rx) = channel();
+            connection.pending.lock().unwrap().insert(request_id, Waiter::Proposal(tx));
+
+            let message = messages::proposal_request(request_id, entry);
+            let write_result = {
+                let mut writer = connection.writer.lock().unwrap();
+                serialize::write_message(&mut *writer, &*message)
+                    .and_then(|_| writer.flush())
+            };
+            if write_result.is_err() {
+                connection.pending.lock().unwrap().remove(&request_id);
+                self.forget_connection(&connection);
+                continue;
+            }
+            *self.leader_connection.lock().unwrap() = Some(connection.clone());
+
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::new(0, 0) => remaining,
+                _ => return Err(RaftError::Timeout),
+            };
+            match rx.recv_timeout(remaining) {
+                Ok(Ok(())) => {
+                    scoped_debug!("recieved response Success");
+                    return Ok(())
+                },
+                Ok(Err(RaftError::UnknownLeader)) => {
+                    scoped_debug!("recieved response UnknownLeader");
+                    self.forget_connection(&connection);
+                },
+                Ok(Err(RaftError::NotLeader(leader))) => {
+                    scoped_debug!("recieved response NotLeader");
+                    self.forget_connection(&connection);
+                    redirect = Some(try!(self.member_for_redirect(&leader)));
+                },
+                // The reader thread hung up because the connection was lost (including a
+                // stalled leader tripping `set_read_timeout`) while our proposal was still
+                // outstanding. Treat it the same as a local recv timeout: a retry rather than
+                // a hard error, since another cluster member may still be reachable.
+                Ok(Err(RaftError::Disconnected)) => {
+                    scoped_debug!("recieved response Disconnected");
+                    self.forget_connection(&connection);
+                },
+                Ok(Err(err)) => return Err(err),
+                // The wait for this specific response timed out. Treat it as a retry rather
+                // than a hard error: the request id is kept for the next attempt, while this
+                // waiter is removed and the connection dropped, so a late response is discarded.
+                Err(_) => {
+                    scoped_debug!("timed out waiting for a response, retrying");
+                    connection.pending.lock().unwrap().remove(&request_id);
+                    self.forget_connection(&connection);
+                },
+            }
+        }
+    }
 
-        let mut members = self.cluster.iter().cloned();
+    /// Issues a read-only query against the `StateMachine`, routed to the leader via the same
+    /// connection/redirect machinery as `propose`, and returns the application-defined result
+    /// bytes it produces. Unlike `propose`, this does not append anything to the replicated
+    /// log: it's a cheaper read path for callers that just want to observe committed state.
+    pub fn query(&self, query: &[u8]) -> Result<Vec<u8>> {
+        scoped_trace!("{:?}: query", self);
+        let deadline = Instant::now() + self.config.propose_timeout;
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let mut redirect = None;
+        let mut backoff = self.config.backoff;
+        let mut passes = 0;
+        let mut tried = HashSet::new();
 
         loop {
-            let mut connection = match self.leader_connection.take() {
-                Some(cxn) => {
-                    scoped_debug!("had existing connection {:?}", cxn.get_ref().peer_addr());
-                    cxn
+            let connection = try!(self.acquire_connection(&mut tried, &mut redirect,
+                                                           &mut backoff, &mut passes, deadline));
+
+            let (tx, rx) = channel();
+            connection.pending.lock().unwrap().insert(request_id, Waiter::Query(tx));
+
+            let message = messages::query_request(request_id, query);
+            let write_result = {
+                let mut writer = connection.writer.lock().unwrap();
+                serialize::write_message(&mut *writer, &*message)
+                    .and_then(|_| writer.flush())
+            };
+            if write_result.is_err() {
+                connection.pending.lock().unwrap().remove(&request_id);
+                self.forget_connection(&connection);
+                continue;
+            }
+            *self.leader_connection.lock().unwrap() = Some(connection.clone());
+
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::new(0, 0) => remaining,
+                _ => return Err(RaftError::Timeout),
+            };
+            match rx.recv_timeout(remaining) {
+                Ok(Ok(result)) => {
+                    scoped_debug!("recieved query response Success");
+                    return Ok(result)
                 },
-                None => {
-                    let leader = try!(members.next().ok_or(RaftError::LeaderSearchExhausted));
-                    scoped_debug!("connecting to potential leader {}", leader);
-                    // Send the preamble.
-                    let preamble = messages::client_connection_preamble(self.id);
-                    let mut stream = BufStream::new(try!(TcpStream::connect(leader)));
-                    try!(serialize::write_message(&mut stream, &*preamble));
-                    stream
+                Ok(Err(RaftError::UnknownLeader)) => {
+                    scoped_debug!("recieved query response UnknownLeader");
+                    self.forget_connection(&connection);
+                },
+                Ok(Err(RaftError::NotLeader(leader))) => {
+                    scoped_debug!("recieved query response NotLeader");
+                    self.forget_connection(&connection);
+                    redirect = Some(try!(self.member_for_redirect(&leader)));
+                },
+                // See the identical arm in `propose`: a lost connection should send this query
+                // to another cluster member, not fail the caller outright.
+                Ok(Err(RaftError::Disconnected)) => {
+                    scoped_debug!("recieved query response Disconnected");
+                    self.forget_connection(&connection);
+                },
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    scoped_debug!("timed out waiting for a query response, retrying");
+                    connection.pending.lock().unwrap().remove(&request_id);
+                    self.forget_connection(&connection);
+                },
+            }
+        }
+    }
+
+    /// Shared connection-acquisition step for `propose` and `query`: follows a pending
+    /// redirect if one was set by a `NotLeader` response, otherwise reuses the current leader
+    /// connection or connects to a cluster member not yet tried this pass, backing off and
+    /// starting a fresh pass once every member has been tried without success. Bounded
+    /// throughout by `deadline`.
+    fn acquire_connection(&self,
+                          tried: &mut HashSet<SocketAddr>,
+                          redirect: &mut Option<ClusterMember>,
+                          backoff: &mut Duration,
+                          passes: &mut usize,
+                          deadline: Instant) -> Result<Arc<LeaderConnection>> {
+        loop {
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::new(0, 0) => remaining,
+                _ => return Err(RaftError::Timeout),
+            };
+
+            if let Some(member) = redirect.take() {
+                // An unreachable redirect target is no worse than an `UnknownLeader`: fall
+                // back to searching the rest of the cluster rather than failing the call.
+                match self.connect(member, remaining) {
+                    Ok(connection) => return Ok(connection),
+                    Err(_) => continue,
                 }
+            }
+
+            if let Some(connection) = self.leader_connection.lock().unwrap().clone() {
+                scoped_debug!("had existing connection {}", connection.addr);
+                return Ok(connection);
+            }
+
+            match self.cluster.iter().find(|m| !tried.contains(&m.addr)).cloned() {
+                Some(member) => {
+                    tried.insert(member.addr);
+                    // One unreachable cluster member shouldn't abort the whole search; treat
+                    // a connect failure like any other dead end and try the next member.
+                    match self.connect(member, remaining) {
+                        Ok(connection) => return Ok(connection),
+                        Err(_) => continue,
+                    }
+                },
+                None => {
+                    *passes += 1;
+                    if *passes > self.config.max_retries {
+                        return Err(RaftError::LeaderSearchExhausted);
+                    }
+                    scoped_debug!("exhausted a pass over the cluster, backing off {:?}", *backoff);
+                    thread::sleep(::std::cmp::min(*backoff, remaining));
+                    *backoff = ::std::cmp::min(*backoff * 2, self.config.propose_timeout);
+                    tried.clear();
+                },
+            }
+        }
+    }
+
+    /// Resolves a `NotLeader` redirect's address string into a `ClusterMember`, reusing the
+    /// configured Noise key if the address happens to match a known cluster member.
+    fn member_for_redirect(&self, leader: &str) -> Result<ClusterMember> {
+        let addr: SocketAddr = try!(leader.parse().map_err(|_| RaftError::UnknownLeader));
+        Ok(self.cluster.iter().find(|m| m.addr == addr).cloned()
+            .unwrap_or_else(|| ClusterMember::plaintext(addr)))
+    }
+
+    /// Establishes a fresh connection to `member`, negotiating a Noise handshake first if
+    /// `member.noise_key` is set, then sends the preamble and spawns the background reader
+    /// that will demultiplex its responses.
+    fn connect(&self, member: ClusterMember, timeout: Duration) -> Result<Arc<LeaderConnection>> {
+        scoped_debug!("connecting to potential leader {}", member.addr);
+        // Bound the connect itself: a member that silently drops the SYN (a dead or
+        // firewalled node, the most common Raft failure mode) must not be allowed to block
+        // past our caller's deadline waiting on the OS's own connect timeout.
+        let stream = try!(TcpStream::connect_timeout(&member.addr, timeout));
+        // Bound how long the background reader can block on a single read so a leader that
+        // stalls mid-response doesn't pin this connection open forever; `propose` itself
+        // already applies its own, possibly shorter, per-call deadline on top of this.
+        try!(stream.set_read_timeout(Some(self.config.propose_timeout)));
+
+        let mut transport = match member.noise_key {
+            Some(server_key) => Transport::Encrypted(try!(self.noise_handshake(stream, &server_key))),
+            None => Transport::Plain(BufStream::new(stream)),
+        };
+        let reader_transport = try!(transport.try_clone());
+
+        let preamble = messages::client_connection_preamble(self.id, SUPPORTED_PROTOCOL_VERSIONS);
+        try!(serialize::write_message(&mut transport, &*preamble));
+        try!(transport.flush());
+
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let negotiated_version = Arc::new(Mutex::new(None));
+        spawn_reader(reader_transport, pending.clone(), negotiated_version.clone());
+
+        let connection = Arc::new(LeaderConnection {
+            addr: member.addr,
+            writer: Mutex::new(transport),
+            pending: pending,
+            negotiated_version: negotiated_version,
+        });
+        *self.leader_connection.lock().unwrap() = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Runs the `Noise_XK` handshake as initiator over `stream`, presenting this client's
+    /// static keypair and verifying that the remote presents `server_key`. Returns the
+    /// resulting transport-mode session wrapped for framed, length-prefixed use.
+    fn noise_handshake(&self, stream: TcpStream, server_key: &[u8; 32]) -> Result<NoiseTransport> {
+        let mut handshake = try!(NoiseBuilder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&self.noise_keypair.private)
+            .remote_public_key(server_key)
+            .build_initiator()
+            .map_err(|_| RaftError::HandshakeFailed));
+
+        let mut inner = BufStream::new(stream);
+        let mut buf = vec![0u8; 1024];
+
+        // -> e, es
+        let len = try!(handshake.write_message(&[], &mut buf).map_err(|_| RaftError::HandshakeFailed));
+        try!(inner.write_u32::<BigEndian>(len as u32));
+        try!(inner.write_all(&buf[..len]));
+        try!(inner.flush());
+
+        // <- e, ee
+        let len = try!(inner.read_u32::<BigEndian>());
+        if len > MAX_NOISE_FRAME_LEN {
+            return Err(RaftError::HandshakeFailed);
+        }
+        let mut ciphertext = vec![0u8; len as usize];
+        try!(inner.read_exact(&mut ciphertext));
+        try!(handshake.read_message(&ciphertext, &mut buf).map_err(|_| RaftError::HandshakeFailed));
+
+        // -> s, se
+        let len = try!(handshake.write_message(&[], &mut buf).map_err(|_| RaftError::HandshakeFailed));
+        try!(inner.write_u32::<BigEndian>(len as u32));
+        try!(inner.write_all(&buf[..len]));
+        try!(inner.flush());
+
+        let session = try!(handshake.into_transport_mode().map_err(|_| RaftError::HandshakeFailed));
+        Ok(NoiseTransport {
+            inner: inner,
+            session: Arc::new(Mutex::new(session)),
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        })
+    }
+
+    /// Drops `connection` from `self` if it is still the current leader connection. Another
+    /// in-flight `propose` may have already replaced it, in which case this is a no-op.
+    fn forget_connection(&self, connection: &Arc<LeaderConnection>) {
+        let mut current = self.leader_connection.lock().unwrap();
+        if let Some(ref existing) = *current {
+            if !Arc::ptr_eq(existing, connection) {
+                return;
+            }
+        } else {
+            return;
+        }
+        *current = None;
+    }
+
+    /// Returns the protocol version negotiated with the current leader connection, if any.
+    /// `None` until a connection has been established and the server's first response has
+    /// been read, or if there is currently no connection at all.
+    pub fn negotiated_protocol_version(&self) -> Option<u32> {
+        let connection = self.leader_connection.lock().unwrap().clone();
+        connection.and_then(|c| *c.negotiated_version.lock().unwrap())
+    }
+}
+
+/// Reads `client_response`s off `reader` until the connection is lost, completing the
+/// waiter registered in `pending` for each response's request id. When the connection
+/// drops, every waiter still outstanding is told so it can be retried elsewhere.
+fn spawn_reader(mut reader: Transport,
+                pending: Arc<Mutex<HashMap<u64, Waiter>>>,
+                negotiated_version: Arc<Mutex<Option<u32>>>) {
+    thread::spawn(move || {
+        let mut version_negotiated = false;
+        loop {
+            let message = match serialize::read_message(&mut reader, ReaderOptions::new()) {
+                Ok(message) => message,
+                Err(_) => break,
             };
-            try!(serialize::write_message(&mut connection, &mut message));
-            try!(connection.flush());
-            let response = try!(serialize::read_message(&mut connection, ReaderOptions::new()));
-            match try!(response.get_root::<client_response::Reader>()).which().unwrap() {
-                client_response::Which::Proposal(Ok(response)) => {
-                    match response.which().unwrap() {
-                        proposal_response::Which::Success(()) => {
-                            scoped_debug!("recieved response Success");
-                            self.leader_connection = Some(connection);
-                            return Ok(())
-                        },
-                        proposal_response::Which::UnknownLeader(()) => {
-                            scoped_debug!("recieved response UnknownLeader");
-                            ()
-                        },
+            let response = match message.get_root::<client_response::Reader>() {
+                Ok(response) => response,
+                Err(_) => break,
+            };
+
+            // The server rides its chosen protocol version on the first `client_response` it
+            // ever sends back; record it, and if it didn't pick anything we advertised, every
+            // waiter on this connection (including the one for this very message) is doomed.
+            if !version_negotiated {
+                version_negotiated = true;
+                let version = response.get_protocol_version();
+                *negotiated_version.lock().unwrap() = Some(version);
+                if !SUPPORTED_PROTOCOL_VERSIONS.contains(&version) {
+                    for (_, waiter) in pending.lock().unwrap().drain() {
+                        waiter.fail(RaftError::VersionMismatch);
+                    }
+                    break;
+                }
+            }
+
+            match response.which() {
+                Ok(client_response::Which::Proposal(Ok(proposal))) => {
+                    let request_id = proposal.get_request_id();
+                    let result = match proposal.which().unwrap() {
+                        proposal_response::Which::Success(()) => Ok(()),
+                        proposal_response::Which::UnknownLeader(()) => Err(RaftError::UnknownLeader),
                         proposal_response::Which::NotLeader(leader) => {
-                            scoped_debug!("recieved response NotLeader");
-                            let mut connection: TcpStream = try!(TcpStream::connect(try!(leader)));
-                            let preamble = messages::client_connection_preamble(self.id);
-                            try!(serialize::write_message(&mut connection, &*preamble));
-                            self.leader_connection = Some(BufStream::new(connection));
+                            Err(RaftError::NotLeader(leader.unwrap_or("").to_owned()))
                         }
+                    };
+                    if let Some(waiter) = pending.lock().unwrap().remove(&request_id) {
+                        waiter.complete_proposal(result);
                     }
                 },
-                _ => panic!("Unexpected message type"), // TODO: return a proper error
+                Ok(client_response::Which::Query(Ok(query))) => {
+                    let request_id = query.get_request_id();
+                    let result = match query.which().unwrap() {
+                        query_response::Which::Success(bytes) => {
+                            bytes.map(|b| b.to_owned()).map_err(|_| RaftError::Disconnected)
+                        },
+                        query_response::Which::UnknownLeader(()) => Err(RaftError::UnknownLeader),
+                        query_response::Which::NotLeader(leader) => {
+                            Err(RaftError::NotLeader(leader.unwrap_or("").to_owned()))
+                        }
+                    };
+                    if let Some(waiter) = pending.lock().unwrap().remove(&request_id) {
+                        waiter.complete_query(result);
+                    }
+                },
+                _ => break,
             }
         }
-    }
+
+        for (_, waiter) in pending.lock().unwrap().drain() {
+            waiter.fail(RaftError::Disconnected);
+        }
+    });
 }
 
 impl fmt::Debug for Client {
@@ -105,16 +693,20 @@ impl fmt::Debug for Client {
 mod test {
     extern crate env_logger;
     use Client;
+    use ClusterMember;
+    use ClientConfig;
     use uuid::Uuid;
     use std::net::{SocketAddr, TcpListener};
     use std::collections::HashSet;
     use std::str::FromStr;
+    use std::sync::Arc;
     use std::thread;
     use std::io::{Read, Write};
     use capnp::{serialize, ReaderOptions};
     use capnp::message::MessageReader;
     use messages;
     use messages_capnp::{connection_preamble, client_request};
+    use RaftError;
 
     #[test]
     fn test_proposal_standalone() {
@@ -122,14 +714,16 @@ mod test {
         let mut cluster = HashSet::new();
         let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
         let test_addr = test_server.local_addr().unwrap();
-        cluster.insert(test_addr);
+        cluster.insert(ClusterMember::plaintext(test_addr));
 
         // TODO: Test if the second server is not in the set.
         let second_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
         let second_addr = second_server.local_addr().unwrap();
-        // cluster.insert(second_addr);
+        // cluster.insert(ClusterMember::plaintext(second_addr));
 
-        let mut client = Client::new(cluster);
+        // Disable the retry-after-exhausted-pass behavior for this test: it exercises
+        // `LeaderSearchExhausted` deterministically after exactly one pass over the cluster.
+        let client = Client::with_config(cluster, ClientConfig { max_retries: 0, ..ClientConfig::default() });
         let client_id = client.id.0.clone();
         let to_propose = b"Bears";
 
@@ -153,14 +747,15 @@ mod test {
             // Expect first proposal! (success!)
             let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
             let request = message.get_root::<client_request::Reader>().unwrap();
-            // Test to make sure request has the right value.
-            if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+            // Test to make sure request has the right value and id.
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
                 scoped_debug!("got proposal");
                 assert_eq!(proposal.get_entry().unwrap(), to_propose);
-            } else { panic!("Invalid request."); }
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
 
             // Send first response! (success!)
-            let response = messages::proposal_response_success();
+            let response = messages::proposal_response_success(request_id, 1);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush();
 
@@ -171,23 +766,43 @@ mod test {
             let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
             let request = message.get_root::<client_request::Reader>().unwrap();
             // Test to make sure request has the right value.
-            if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
                 scoped_debug!("got proposal");
                 assert_eq!(proposal.get_entry().unwrap(), to_propose);
-            } else { panic!("Invalid request."); }
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
 
-            // Send response! (unknown leader!) Client should drop connection.
-            let response = messages::proposal_response_unknown_leader();
+            // Send response! (unknown leader!) Client should drop connection and retry,
+            // preserving the same request id.
+            let response = messages::proposal_response_unknown_leader(request_id, 1);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush();
 
             let (mut connection, _)  = test_server.accept().unwrap();
+
+            // Expect Preamble on the retried connection.
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let preamble = message.get_root::<connection_preamble::Reader>().unwrap();
+            if let connection_preamble::id::Which::Client(Ok(id)) = preamble.get_id().which().unwrap() {
+                assert_eq!(Uuid::from_bytes(id).unwrap(), client_id);
+            } else { panic!("Invalid preamble."); }
+
+            // Expect the retried proposal, with the request id preserved, and report
+            // unknown leader again so the cluster member list is exhausted.
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+                assert_eq!(proposal.get_entry().unwrap(), to_propose);
+                assert_eq!(proposal.get_request_id(), request_id);
+            } else { panic!("Invalid request."); }
+            let response = messages::proposal_response_unknown_leader(request_id, 1);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush();
 
+            let (mut connection, _)  = test_server.accept().unwrap();
+
             // Third Proposal should report NotLeader. Client should choose the server we direct it to.
             scoped_debug!("Should get preamble and proposal. Responds NotLeader.");
-            let (mut connection, _)  = test_server.accept().unwrap();
 
             // Expect Preamble.
             let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
@@ -202,13 +817,14 @@ mod test {
             let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
             let request = message.get_root::<client_request::Reader>().unwrap();
             // Test to make sure request has the right value.
-            if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
                 scoped_debug!("got second proposal");
                 assert_eq!(proposal.get_entry().unwrap(), to_propose);
-            } else { panic!("Invalid request."); }
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
 
             // Send response! (not leader!)
-            let response = messages::proposal_response_not_leader(&format!("{}", second_addr));
+            let response = messages::proposal_response_not_leader(request_id, 1, &format!("{}", second_addr));
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush();
 
@@ -226,17 +842,19 @@ mod test {
                 assert_eq!(Uuid::from_bytes(id).unwrap(), client_id);
             } else { panic!("Invalid preamble."); }
 
-            // Expect proposal! (again!)
+            // Expect proposal! (again!) -- and the request id should have been preserved
+            // across the NotLeader redirect.
             let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
             let request = message.get_root::<client_request::Reader>().unwrap();
             // Test to make sure request has the right value.
             if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
                 scoped_debug!("got third proposal");
                 assert_eq!(proposal.get_entry().unwrap(), to_propose);
+                assert_eq!(proposal.get_request_id(), request_id);
             } else { panic!("Invalid request."); }
 
             // Send final response! (Success!)
-            let response = messages::proposal_response_success();
+            let response = messages::proposal_response_success(request_id, 1);
             serialize::write_message(&mut connection, &*response).unwrap();
 
         });
@@ -244,7 +862,6 @@ mod test {
         // Should be ok
         scoped_debug!("first starting");
         client.propose(to_propose).unwrap();
-        assert!(client.leader_connection.is_some());
         scoped_debug!("first done");
         // Should be err
         scoped_debug!("second starting");
@@ -253,9 +870,303 @@ mod test {
         // Should be ok, change leader connection.
         scoped_debug!("third starting");
         client.propose(to_propose).unwrap();
-        assert!(client.leader_connection.is_some());
         scoped_debug!("third done");
 
         child.join().unwrap();
     }
+
+    #[test]
+    fn test_concurrent_proposals_correlated_by_request_id() {
+        setup_test!("test_concurrent_proposals_correlated_by_request_id");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(ClusterMember::plaintext(test_addr));
+
+        let client = Arc::new(Client::with_config(cluster, ClientConfig { max_retries: 0, ..ClientConfig::default() }));
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+
+            // Establish the connection with a single warm-up proposal before the two
+            // concurrent ones below, so both `propose` calls find the connection already
+            // cached and pipeline onto it rather than racing each other to create it.
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
+            let response = messages::proposal_response_success(request_id, 1);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+
+            // Read the two genuinely concurrent proposals, in whatever order they arrive on
+            // the wire, and figure out which request id belongs to which by its entry.
+            let mut apple_id = None;
+            let mut banana_id = None;
+            for _ in 0..2 {
+                let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+                let request = message.get_root::<client_request::Reader>().unwrap();
+                if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+                    match proposal.get_entry().unwrap() {
+                        b"Apple" => apple_id = Some(proposal.get_request_id()),
+                        b"Banana" => banana_id = Some(proposal.get_request_id()),
+                        other => panic!("unexpected entry {:?}", other),
+                    }
+                } else { panic!("Invalid request."); }
+            }
+            let apple_id = apple_id.unwrap();
+            let banana_id = banana_id.unwrap();
+
+            // Answer out of order -- banana's response goes out first -- and make apple's
+            // response a hard error, so a caller matched up by position/arrival order
+            // instead of request id would get the wrong outcome for its own proposal.
+            let response = messages::proposal_response_success(banana_id, 1);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+
+            let response = messages::proposal_response_not_leader(apple_id, 1, "not-a-real-address");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+        });
+
+        client.propose(b"warmup").unwrap();
+
+        let apple_client = client.clone();
+        let apple_thread = thread::spawn(move || apple_client.propose(b"Apple"));
+        let banana_client = client.clone();
+        let banana_thread = thread::spawn(move || banana_client.propose(b"Banana"));
+
+        // Apple's response is an unparseable redirect, so it should come back as
+        // `UnknownLeader`; banana's is a clean success. If the client were matching
+        // responses to callers positionally rather than by request id, one of these two
+        // assertions would fail depending on write/read interleaving.
+        assert!(apple_thread.join().unwrap().is_err());
+        assert!(banana_thread.join().unwrap().is_ok());
+
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_query_standalone() {
+        setup_test!("test_query_standalone");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(ClusterMember::plaintext(test_addr));
+
+        let second_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let second_addr = second_server.local_addr().unwrap();
+
+        let client = Client::with_config(cluster, ClientConfig { max_retries: 0, ..ClientConfig::default() });
+        let to_query = b"how many bears?";
+        let query_result = b"seven bears";
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+
+            // Expect Preamble, then a query reporting NotLeader, redirecting to the second server.
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            let request_id = if let client_request::Which::Query(Ok(query)) = request.which().unwrap() {
+                assert_eq!(query.get_query().unwrap(), to_query);
+                query.get_request_id()
+            } else { panic!("Invalid request."); };
+
+            let response = messages::query_response_not_leader(request_id, 1, &format!("{}", second_addr));
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+
+            // Redirected to the second server, which answers the query.
+            let (mut connection, _) = second_server.accept().unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            if let client_request::Which::Query(Ok(query)) = request.which().unwrap() {
+                assert_eq!(query.get_query().unwrap(), to_query);
+                assert_eq!(query.get_request_id(), request_id);
+            } else { panic!("Invalid request."); }
+
+            let response = messages::query_response_success(request_id, 1, query_result);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+        });
+
+        let result = client.query(to_query).unwrap();
+        assert_eq!(result, query_result);
+
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_propose_timeout() {
+        setup_test!("test_propose_timeout");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(ClusterMember::plaintext(test_addr));
+
+        // A leader that accepts the connection and then never responds must not hang `propose`
+        // forever: it should come back once `propose_timeout` has elapsed and the single
+        // cluster member has been exhausted (`max_retries: 0`).
+        let config = ClientConfig {
+            propose_timeout: ::std::time::Duration::from_millis(200),
+            max_retries: 0,
+            backoff: ::std::time::Duration::from_millis(10),
+        };
+        let client = Client::with_config(cluster, config);
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<client_request::Reader>().unwrap();
+            // Never respond; hold the connection open a bit past `propose_timeout` so the
+            // client's read times out rather than seeing a clean disconnect.
+            thread::sleep(::std::time::Duration::from_millis(400));
+        });
+
+        let result = client.propose(b"Bears");
+        assert!(result.is_err());
+        match result {
+            Err(RaftError::Timeout) | Err(RaftError::LeaderSearchExhausted) => {},
+            other => panic!("expected Timeout or LeaderSearchExhausted, got {:?}", other),
+        }
+
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        setup_test!("test_version_mismatch");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(ClusterMember::plaintext(test_addr));
+
+        let client = Client::with_config(cluster, ClientConfig { max_retries: 0, ..ClientConfig::default() });
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
+
+            // Respond with a protocol version this client doesn't advertise support for.
+            let response = messages::proposal_response_success(request_id, 99);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush();
+        });
+
+        let result = client.propose(b"Bears");
+        assert_eq!(result, Err(RaftError::VersionMismatch));
+        // Recorded even though it was rejected, since the reader stamps it before checking
+        // compatibility -- it's evidence of what the server actually offered.
+        assert_eq!(client.negotiated_protocol_version(), Some(99));
+
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_noise_transport_proposal() {
+        setup_test!("test_noise_transport_proposal");
+        use snow::Builder as NoiseBuilder;
+        use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind(SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        let server_keypair = NoiseBuilder::new("Noise_XK_25519_ChaChaPoly_BLAKE2b".parse().unwrap())
+            .generate_keypair().unwrap();
+        let mut server_public_key = [0u8; 32];
+        server_public_key.copy_from_slice(&server_keypair.public);
+        cluster.insert(ClusterMember::encrypted(test_addr, server_public_key));
+
+        let client = Client::with_config(cluster, ClientConfig { max_retries: 0, ..ClientConfig::default() });
+        let to_propose = b"Bears";
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+
+            // Run the `Noise_XK` handshake as the responder, mirroring the client's initiator
+            // side in `Client::noise_handshake`.
+            let mut handshake = NoiseBuilder::new("Noise_XK_25519_ChaChaPoly_BLAKE2b".parse().unwrap())
+                .local_private_key(&server_keypair.private)
+                .build_responder()
+                .unwrap();
+            let mut buf = vec![0u8; 1024];
+
+            // <- e, es
+            let len = connection.read_u32::<BigEndian>().unwrap() as usize;
+            let mut ciphertext = vec![0u8; len];
+            connection.read_exact(&mut ciphertext).unwrap();
+            handshake.read_message(&ciphertext, &mut buf).unwrap();
+
+            // -> e, ee
+            let len = handshake.write_message(&[], &mut buf).unwrap();
+            connection.write_u32::<BigEndian>(len as u32).unwrap();
+            connection.write_all(&buf[..len]).unwrap();
+            connection.flush().unwrap();
+
+            // <- s, se
+            let len = connection.read_u32::<BigEndian>().unwrap() as usize;
+            let mut ciphertext = vec![0u8; len];
+            connection.read_exact(&mut ciphertext).unwrap();
+            handshake.read_message(&ciphertext, &mut buf).unwrap();
+
+            let mut session = handshake.into_transport_mode().unwrap();
+
+            // Decrypt and read the preamble, matching `NoiseTransport`'s framing: one
+            // length-prefixed Noise message per capnp message.
+            let len = connection.read_u32::<BigEndian>().unwrap() as usize;
+            let mut ciphertext = vec![0u8; len];
+            connection.read_exact(&mut ciphertext).unwrap();
+            let mut plaintext = vec![0u8; len];
+            let n = session.read_message(&ciphertext, &mut plaintext).unwrap();
+            plaintext.truncate(n);
+            let message = serialize::read_message(&mut &plaintext[..], ReaderOptions::new()).unwrap();
+            message.get_root::<connection_preamble::Reader>().unwrap();
+
+            // Decrypt and read the proposal the same way.
+            let len = connection.read_u32::<BigEndian>().unwrap() as usize;
+            let mut ciphertext = vec![0u8; len];
+            connection.read_exact(&mut ciphertext).unwrap();
+            let mut plaintext = vec![0u8; len];
+            let n = session.read_message(&ciphertext, &mut plaintext).unwrap();
+            plaintext.truncate(n);
+            let message = serialize::read_message(&mut &plaintext[..], ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            let request_id = if let client_request::Which::Proposal(Ok(proposal)) = request.which().unwrap() {
+                assert_eq!(proposal.get_entry().unwrap(), to_propose);
+                proposal.get_request_id()
+            } else { panic!("Invalid request."); };
+
+            // Encrypt and send the response, again matching `NoiseTransport`'s framing.
+            let response = messages::proposal_response_success(request_id, 1);
+            let mut response_bytes = Vec::new();
+            serialize::write_message(&mut response_bytes, &*response).unwrap();
+            let mut response_ciphertext = vec![0u8; response_bytes.len() + 16];
+            let len = session.write_message(&response_bytes, &mut response_ciphertext).unwrap();
+            connection.write_u32::<BigEndian>(len as u32).unwrap();
+            connection.write_all(&response_ciphertext[..len]).unwrap();
+            connection.flush().unwrap();
+        });
+
+        client.propose(to_propose).unwrap();
+
+        child.join().unwrap();
+    }
 }